@@ -0,0 +1,177 @@
+//! A small client of the GitLab REST API, for the titles and details shown by `#42+` and `#42+s`.
+//!
+//! The token is read from the `GITLAB_TOKEN` environment variable, public projects work without
+//! one. Lookups that fail are logged and the reference falls back to the plain link, and after
+//! the first connection error the rest of the build doesn't try again. Answers are kept in the
+//! [`Cache`] between builds.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::Cache;
+use crate::config::Server;
+
+/// Environment variable holding a personal, project or group access token.
+pub(crate) const TOKEN_VAR: &str = "GITLAB_TOKEN";
+
+/// Issues, merge requests, milestones and labels, as far as references show them.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Issuable {
+    /// The name, for labels
+    #[serde(alias = "name")]
+    pub title: String,
+    /// `opened`, `closed`, `merged` or `locked`, `active` or `closed` for milestones
+    #[serde(default)]
+    pub state: String,
+    #[serde(default)]
+    pub assignees: Vec<User>,
+    pub milestone: Option<MilestoneInfo>,
+    /// Only on issues, `on_track`, `needs_attention` or `at_risk`
+    pub health_status: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct User {
+    pub name: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct MilestoneInfo {
+    pub title: String,
+}
+
+/// What an issuable reference points to, by its path in the API.
+#[derive(Clone, Copy)]
+pub(crate) enum IssuableKind {
+    Issue,
+    MergeRequest,
+    Milestone,
+    Label,
+}
+
+impl IssuableKind {
+    pub fn path(self) -> &'static str {
+        match self {
+            Self::Issue => "issues",
+            Self::MergeRequest => "merge_requests",
+            Self::Milestone => "milestones",
+            Self::Label => "labels",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Issue => "issue",
+            Self::MergeRequest => "merge request",
+            Self::Milestone => "milestone",
+            Self::Label => "label",
+        }
+    }
+}
+
+pub(crate) struct Client {
+    agent: ureq::Agent,
+    token: Option<String>,
+    /// Only answer from the cache, set by `offline = true` or after a connection error, so that
+    /// an offline build doesn't wait on every reference
+    offline: Cell<bool>,
+    cache: Cache,
+    /// URLs that failed in this build, not to be asked again
+    failed: RefCell<HashSet<String>>,
+}
+
+impl Client {
+    pub fn new(cache: Cache, token: Option<String>, offline: bool) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(10)).build(),
+            token,
+            offline: Cell::new(offline),
+            cache,
+            failed: RefCell::new(HashSet::new()),
+        }
+    }
+
+    /// Looks up the issue, merge request, milestone or label `id` of the project at `path` on `server`.
+    /// `Some(None)` means GitLab doesn't know it, `None` that it couldn't be asked.
+    pub fn issuable(&self, server: &Server, path: &str, kind: IssuableKind, id: &str) -> Option<Option<Issuable>> {
+        let key = format!("{}/{path}/{}/{id}", server.url, kind.path());
+        if let Some(value) = self.cache.get(&key, self.offline.get()) {
+            return Some(value);
+        }
+        let project_url = format!("{}/api/v4/projects/{}", server.url, crate::url_encode(path));
+        let url = match kind {
+            // Milestones are only addressed by their global id, the iid is a filter
+            IssuableKind::Milestone => format!("{project_url}/milestones?iids[]={id}"),
+            _ => format!("{project_url}/{}/{id}", kind.path()),
+        };
+        if self.offline.get() || self.failed.borrow().contains(&url) {
+            return None;
+        }
+        let response = match kind {
+            IssuableKind::Milestone => self.get::<Vec<Issuable>>(&url).map(|r| r.and_then(|m| m.into_iter().next())),
+            _ => self.get(&url),
+        };
+        match response {
+            Ok(value) => {
+                self.cache.insert(key, value.clone());
+                Some(value)
+            }
+            Err(()) => {
+                self.failed.borrow_mut().insert(url);
+                // Still better than nothing
+                self.cache.get(&key, true)
+            }
+        }
+    }
+
+    /// Writes the cache, once the book is done.
+    pub fn save(&self) {
+        self.cache.save();
+    }
+
+    /// `Ok(None)` when GitLab doesn't know the reference, `Err` when it couldn't be asked.
+    fn get<T: DeserializeOwned>(&self, url: &str) -> Result<Option<T>, ()> {
+        let mut request = self.agent.get(url);
+        if let Some(token) = &self.token {
+            request = request.set("PRIVATE-TOKEN", token);
+        }
+        match request.call() {
+            Ok(response) => response.into_json()
+                .map(Some)
+                .map_err(|e| log::warn!("unexpected response from {}: {}", url, e)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(ureq::Error::Status(status, _)) => {
+                log::warn!("GitLab API returned {} for {}", status, url);
+                Err(())
+            }
+            Err(e) => {
+                log::warn!("can't reach the GitLab API, showing plain references: {}", e);
+                self.offline.set(true);
+                Err(())
+            }
+        }
+    }
+}
+
+impl Issuable {
+    /// The details GitLab shows for `+s`, e.g. `Alice, Bob • v1.2 • On track`.
+    pub fn summary(&self) -> Vec<String> {
+        let mut parts = vec![];
+        if !self.assignees.is_empty() {
+            parts.push(self.assignees.iter().map(|u| u.name.as_str()).collect::<Vec<_>>().join(", "));
+        }
+        if let Some(milestone) = &self.milestone {
+            parts.push(milestone.title.clone());
+        }
+        if let Some(health) = self.health_status.as_deref().filter(|h| !h.is_empty()) {
+            let mut health = health.replace('_', " ");
+            health[..1].make_ascii_uppercase();
+            parts.push(health);
+        }
+        parts
+    }
+}
@@ -0,0 +1,86 @@
+//! Keeps the answers of the GitLab API between builds, so that `mdbook serve` doesn't look up
+//! every reference of the book again on each change.
+//!
+//! The cache is a single JSON file, by default `.gitlab-link-cache.json` next to `book.toml`. It
+//! isn't kept in the build directory, which the HTML renderer empties on every build. CI jobs can
+//! share it as an artifact or through the `cache:` of the pipeline.
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::Issuable;
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// Seconds since the Unix epoch
+    fetched_at: u64,
+    /// `None` when GitLab doesn't know the reference
+    value: Option<Issuable>,
+}
+
+pub(crate) struct Cache {
+    path: PathBuf,
+    ttl: Duration,
+    /// By `<server>/<project>/<kind>/<id>`
+    entries: RefCell<BTreeMap<String, Entry>>,
+    changed: Cell<bool>,
+}
+
+impl Cache {
+    /// Reads the cache at `path`, a missing or unreadable file is an empty cache.
+    pub fn load(path: PathBuf, ttl: Duration) -> Self {
+        let entries = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| log::warn!("ignoring the invalid cache {}: {}", path.display(), e))
+                .unwrap_or_default(),
+            Err(_) => BTreeMap::new(),
+        };
+        Self {
+            path,
+            ttl,
+            entries: RefCell::new(entries),
+            changed: Cell::new(false),
+        }
+    }
+
+    /// The cached answer for `key`, `Some(None)` if GitLab didn't know it. Expired entries are
+    /// only returned when `stale` is set.
+    pub fn get(&self, key: &str, stale: bool) -> Option<Option<Issuable>> {
+        let entries = self.entries.borrow();
+        let entry = entries.get(key)?;
+        if !stale && now().saturating_sub(entry.fetched_at) >= self.ttl.as_secs() {
+            return None;
+        }
+        Some(entry.value.clone())
+    }
+
+    pub fn insert(&self, key: String, value: Option<Issuable>) {
+        self.entries.borrow_mut().insert(key, Entry { fetched_at: now(), value });
+        self.changed.set(true);
+    }
+
+    /// Writes the cache back if anything was added.
+    pub fn save(&self) {
+        if !self.changed.get() {
+            return;
+        }
+        let result = self.path.parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| {
+                let data = serde_json::to_vec_pretty(&*self.entries.borrow())?;
+                std::fs::write(&self.path, data)
+            });
+        match result {
+            Ok(()) => self.changed.set(false),
+            Err(e) => log::warn!("can't write the cache {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
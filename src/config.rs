@@ -0,0 +1,371 @@
+//! Resolves the settings of the preprocessor from the `[preprocessor.gitlab-link]` table in
+//! `book.toml` and the GitLab CI environment.
+//!
+//! The CI variables are read when the preprocessor runs, and by default win over `book.toml` so
+//! the same book builds correct links in any pipeline. Books that document another project than
+//! the one building them set `prefer-book-config = true` to reverse that. Whatever is still
+//! missing after that is derived from the git remote of the book.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+use mdbook::errors::{Error, Result};
+use serde::Deserialize;
+
+use crate::api::{Client, TOKEN_VAR};
+use crate::cache::Cache;
+use crate::directive::ChapterDirective;
+use crate::remote::{host, Remote};
+
+type Table = toml::map::Map<String, toml::Value>;
+
+/// Where the answers of the GitLab API are kept, relative to the book root.
+const DEFAULT_CACHE: &str = ".gitlab-link-cache.json";
+
+/// How long the answers of the GitLab API are kept by default, a day.
+const DEFAULT_CACHE_TTL: u64 = 24 * 60 * 60;
+
+/// Keys mdbook itself reads from every `[preprocessor.*]` table.
+const MDBOOK_KEYS: &[&str] = &["command", "renderers", "before", "after", "optional"];
+
+/// The `[preprocessor.gitlab-link]` table as written in `book.toml`.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct BookConfig {
+    gitlab_server_url: Option<String>,
+    gitlab_project_namespace: Option<String>,
+    gitlab_project_name: Option<String>,
+    #[serde(default)]
+    prefer_book_config: bool,
+    git_remote: Option<GitRemote>,
+    commit_min_length: Option<usize>,
+    #[serde(default)]
+    aliases: BTreeMap<String, String>,
+    #[serde(default)]
+    expand_aliases: bool,
+    #[serde(default)]
+    servers: BTreeMap<String, ServerConfig>,
+    #[serde(default)]
+    skip: Vec<Container>,
+    #[serde(default)]
+    link_headings: bool,
+    #[serde(default)]
+    bare_snippets: bool,
+    #[serde(default)]
+    shorten_urls: bool,
+    /// Set to `false` to never call the GitLab API, `#42+` then shows like `#42`
+    #[serde(default = "default_true")]
+    api: bool,
+    cache: Option<PathBuf>,
+    /// Seconds
+    cache_ttl: Option<u64>,
+    /// Only use the cache, never call the API
+    #[serde(default)]
+    offline: bool,
+    #[serde(default)]
+    strict: bool,
+    #[serde(default)]
+    check_existence: bool,
+    state: Option<StateStyle>,
+    /// Set to `false` to only warn about unknown keys, e.g. while upgrading
+    #[serde(default = "default_true")]
+    deny_unknown_keys: bool,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+/// Markdown elements whose text can be left alone with `skip`, on top of code blocks, links and
+/// images that are always skipped, and headings unless `link-headings` is set.
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Container {
+    BlockQuote,
+    List,
+    Table,
+    FootnoteDefinition,
+    Emphasis,
+    Strong,
+    Strikethrough,
+}
+
+/// How the state of issues and merge requests is shown, `#42 (closed)`.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum StateStyle {
+    /// `(closed)` or `(merged)` after the reference, like GitLab
+    Suffix,
+    /// A `gitlab-link-<state>` class on the link, for the CSS of the book
+    Class,
+    /// An emoji after the reference
+    Emoji,
+}
+
+/// A `[preprocessor.gitlab-link.servers.<name>]` table.
+#[derive(Deserialize)]
+struct ServerConfig {
+    url: String,
+    #[serde(default)]
+    namespace: String,
+    #[serde(default)]
+    project: String,
+    /// Top-level namespaces that live on this server
+    #[serde(default)]
+    namespaces: Vec<String>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GitRemote {
+    Name(String),
+    /// `false` turns the detection off, `true` means `origin`
+    Enabled(bool),
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// A GitLab instance, with the project that references without one point to.
+#[derive(Clone)]
+pub(crate) struct Server {
+    pub url: String,
+    pub namespace: String,
+    pub project: String,
+}
+
+/// What an alias stands for, written `path` or `server:path` in `book.toml`.
+#[derive(Clone)]
+pub(crate) struct Alias {
+    /// One of the named servers, `None` for the default one
+    pub server: Option<String>,
+    /// Path of the project or group, empty for the default project of the server
+    pub path: String,
+}
+
+#[derive(Clone)]
+pub(crate) struct Config {
+    /// The server of the book, and the project references without one point to
+    pub server: Server,
+    /// Other GitLab instances, by name
+    pub servers: BTreeMap<String, Server>,
+    /// Names of the servers hosting a top-level namespace
+    routes: BTreeMap<String, String>,
+    /// Shortest bare commit SHA that is turned into a link
+    pub commit_min_length: usize,
+    /// Short names for the full paths of projects or groups, `tfm#12`
+    pub aliases: BTreeMap<String, Alias>,
+    /// Show the full path instead of the alias in the link text
+    pub expand_aliases: bool,
+    /// Elements to leave alone besides the built-in ones
+    pub skip: Vec<Container>,
+    /// Link references in headings too
+    pub link_headings: bool,
+    /// Link `$42` without a project, which is more often money
+    pub bare_snippets: bool,
+    /// Show bare GitLab URLs as references, `https://gitlab.example.com/group/project/-/issues/42`
+    /// as `group/project#42`
+    pub shorten_urls: bool,
+    /// Client of the GitLab API for `#42+` and `#42+s`, unless turned off
+    pub api: Option<Rc<Client>>,
+    /// Fail on references that can't be linked for missing settings
+    pub strict: bool,
+    /// Fail on issues, merge requests and milestones that GitLab doesn't know
+    pub check_existence: bool,
+    /// Show the state of issues and merge requests
+    pub state: Option<StateStyle>,
+}
+
+impl BookConfig {
+    fn parse(cfg: Option<&Table>) -> Result<Self> {
+        let table = cfg.cloned().unwrap_or_default();
+        let config: Self = toml::Value::Table(table).try_into()
+            .map_err(|e| Error::msg(format!("invalid [preprocessor.gitlab-link] config: {e}")))?;
+
+        let unknown = config.unknown.keys()
+            .filter(|k| !MDBOOK_KEYS.contains(&k.as_str()))
+            .cloned()
+            .chain(config.servers.iter().flat_map(|(name, server)| {
+                server.unknown.keys().map(move |key| format!("servers.{name}.{key}"))
+            }));
+        for key in unknown {
+            if config.deny_unknown_keys {
+                return Err(Error::msg(format!(
+                    "unknown key `{key}` in [preprocessor.gitlab-link], set `deny-unknown-keys = false` to ignore it"
+                )));
+            }
+            log::warn!("ignoring unknown key `{}` in [preprocessor.gitlab-link]", key);
+        }
+
+        if let Some(url) = &config.gitlab_server_url {
+            validate_url("gitlab-server-url", url)?;
+        }
+        let mut routed = BTreeMap::new();
+        for (name, server) in &config.servers {
+            validate_url(&format!("servers.{name}.url"), &server.url)?;
+            for namespace in &server.namespaces {
+                if let Some(other) = routed.insert(namespace.as_str(), name.as_str()) {
+                    return Err(Error::msg(format!(
+                        "namespace `{namespace}` is in `servers.{other}.namespaces` and `servers.{name}.namespaces`"
+                    )));
+                }
+            }
+        }
+
+        if let Some(n) = config.commit_min_length {
+            if !(7..=40).contains(&n) {
+                return Err(Error::msg(format!("`commit-min-length` must be between 7 and 40, got {n}")));
+            }
+        }
+
+        for (alias, value) in &config.aliases {
+            let (server, path) = split_alias(value);
+            if server.is_some_and(|name| !config.servers.contains_key(name)) {
+                return Err(Error::msg(format!("`aliases.{alias}` refers to an unknown server in {value:?}")));
+            }
+            if (server.is_none() && path.is_empty()) || path.contains(char::is_whitespace) {
+                return Err(Error::msg(format!("`aliases.{alias}` must be a project or group path, got {value:?}")));
+            }
+        }
+
+        if config.check_existence && !config.api {
+            return Err(Error::msg("`check-existence` needs the GitLab API, remove `api = false`"));
+        }
+        if config.state.is_some() && !config.api {
+            return Err(Error::msg("`state` needs the GitLab API, remove `api = false`"));
+        }
+
+        Ok(config)
+    }
+}
+
+impl Config {
+    pub fn new(cfg: Option<&Table>, root: &Path) -> Result<Self> {
+        let book = BookConfig::parse(cfg)?;
+
+        let get = |from_book: &Option<String>, env: &str| -> Option<String> {
+            // An empty variable is as good as an unset one
+            let from_env = || std::env::var(env).ok().filter(|v| !v.is_empty());
+            if book.prefer_book_config {
+                from_book.clone().or_else(from_env)
+            } else {
+                from_env().or_else(|| from_book.clone())
+            }
+        };
+
+        let mut server_url = get(&book.gitlab_server_url, "CI_SERVER_URL");
+        let mut namespace = get(&book.gitlab_project_namespace, "CI_PROJECT_NAMESPACE");
+        let mut project = get(&book.gitlab_project_name, "CI_PROJECT_NAME");
+        if server_url.is_none() || namespace.is_none() || project.is_none() {
+            let remote_name = match &book.git_remote {
+                Some(GitRemote::Name(name)) => Some(name.as_str()),
+                Some(GitRemote::Enabled(false)) => None,
+                Some(GitRemote::Enabled(true)) | None => Some("origin"),
+            };
+            match remote_name.and_then(|name| Remote::detect(root, name)) {
+                // A remote on another host says nothing about the project on the configured server
+                Some(remote) if server_url.as_deref().is_some_and(|url| host(url) != host(&remote.server_url)) => {
+                    log::info!("ignoring the git remote on {}, it's not on {}",
+                        remote.server_url, server_url.as_deref().unwrap_or_default());
+                }
+                Some(remote) => {
+                    log::debug!("using git remote for the missing settings: {}/{}/{}",
+                        remote.server_url, remote.namespace, remote.project);
+                    server_url = server_url.or(Some(remote.server_url));
+                    namespace = namespace.or(Some(remote.namespace));
+                    project = project.or(Some(remote.project));
+                }
+                None => {}
+            }
+        }
+
+        let routes = book.servers.iter()
+            .flat_map(|(name, server)| server.namespaces.iter().map(move |n| (n.clone(), name.clone())))
+            .collect();
+
+        Ok(Self {
+            server: Server {
+                url: server_url.unwrap_or_default().trim_end_matches('/').to_string(),
+                namespace: namespace.unwrap_or_default(),
+                project: project.unwrap_or_default(),
+            },
+            servers: book.servers.into_iter()
+                .map(|(name, server)| (name, Server {
+                    url: server.url.trim_end_matches('/').to_string(),
+                    namespace: server.namespace,
+                    project: server.project,
+                }))
+                .collect(),
+            routes,
+            commit_min_length: book.commit_min_length.unwrap_or(7),
+            aliases: book.aliases.iter()
+                .map(|(alias, value)| {
+                    let (server, path) = split_alias(value);
+                    (alias.clone(), Alias { server: server.map(String::from), path: path.to_string() })
+                })
+                .collect(),
+            expand_aliases: book.expand_aliases,
+            skip: book.skip,
+            link_headings: book.link_headings,
+            bare_snippets: book.bare_snippets,
+            shorten_urls: book.shorten_urls,
+            api: book.api.then(|| {
+                let cache = Cache::load(
+                    root.join(book.cache.as_deref().unwrap_or(Path::new(DEFAULT_CACHE))),
+                    Duration::from_secs(book.cache_ttl.unwrap_or(DEFAULT_CACHE_TTL)),
+                );
+                let token = std::env::var(TOKEN_VAR).ok().filter(|t| !t.is_empty());
+                Rc::new(Client::new(cache, token, book.offline))
+            }),
+            strict: book.strict,
+            check_existence: book.check_existence,
+            state: book.state,
+        })
+    }
+
+    /// The config of a chapter with a `<!-- gitlab-link: ... -->` directive.
+    pub fn for_chapter(&self, directive: &ChapterDirective) -> Result<Self> {
+        let mut cfg = self.clone();
+        if let Some(name) = &directive.server {
+            cfg.server = self.servers.get(name)
+                .ok_or_else(|| Error::msg(format!("unknown server `{name}` in the gitlab-link directive")))?
+                .clone();
+        }
+        if let Some(path) = &directive.project {
+            let (namespace, project) = path.rsplit_once('/')
+                .ok_or_else(|| Error::msg(format!("`project` must be a full path like `group/project`, got `{path}`")))?;
+            cfg.server.namespace = namespace.to_string();
+            cfg.server.project = project.to_string();
+        }
+        Ok(cfg)
+    }
+
+    /// The server hosting `path`, by its top-level namespace.
+    pub fn server_for(&self, path: &str) -> &Server {
+        let top = path.split('/').next().unwrap_or(path);
+        self.routes.get(top)
+            .and_then(|name| self.servers.get(name))
+            .unwrap_or(&self.server)
+    }
+}
+
+/// Splits `server:path` into its parts, the server is optional.
+fn split_alias(value: &str) -> (Option<&str>, &str) {
+    match value.split_once(':') {
+        Some((server, path)) => (Some(server), path.trim_matches('/')),
+        None => (None, value.trim_matches('/')),
+    }
+}
+
+fn validate_url(key: &str, url: &str) -> Result<()> {
+    let host = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://"));
+    if !host.is_some_and(|h| !h.is_empty() && !h.starts_with('/')) {
+        return Err(Error::msg(format!(
+            "`{key}` must be an http(s) URL like \"https://gitlab.example.com\", got {url:?}"
+        )));
+    }
+    Ok(())
+}
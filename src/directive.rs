@@ -0,0 +1,46 @@
+//! Per-chapter settings, given by a comment at the very top of the chapter:
+//!
+//! ```markdown
+//! <!-- gitlab-link: project=group/other server=public -->
+//! ```
+//!
+//! - `project=<path>` is the project that references without one point to in this chapter
+//! - `server=<name>` is one of the named servers, with its default project
+//! - `enabled=false` leaves the chapter untouched
+
+use mdbook::errors::{Error, Result};
+use regex::Regex;
+
+#[derive(Default)]
+pub(crate) struct ChapterDirective {
+    pub project: Option<String>,
+    pub server: Option<String>,
+    pub enabled: bool,
+}
+
+impl ChapterDirective {
+    /// Reads the directive at the top of `content`, and returns it with the rest of the chapter.
+    /// `re` is `GitlabLink::directive_re`, with the settings in the `settings` group.
+    pub fn parse<'c>(re: &Regex, content: &'c str) -> Result<(Self, &'c str)> {
+        let mut directive = Self { enabled: true, ..Self::default() };
+        let caps = match re.captures(content) {
+            // `<!-- gitlab-link:off -->` starts a region, it isn't a directive
+            Some(caps) if !matches!(caps["settings"].trim(), "on" | "off") => caps,
+            _ => return Ok((directive, content)),
+        };
+
+        for setting in caps["settings"].split(|c: char| c == ',' || c.is_whitespace()).filter(|s| !s.is_empty()) {
+            let (key, value) = setting.split_once('=')
+                .ok_or_else(|| Error::msg(format!("expected `key=value` in the gitlab-link directive, got `{setting}`")))?;
+            match key {
+                "project" => directive.project = Some(value.trim_matches('/').to_string()),
+                "server" => directive.server = Some(value.to_string()),
+                "enabled" => directive.enabled = value.parse()
+                    .map_err(|_| Error::msg(format!("`enabled` must be true or false, got `{value}`")))?,
+                _ => return Err(Error::msg(format!("unknown key `{key}` in the gitlab-link directive"))),
+            }
+        }
+
+        Ok((directive, &content[caps.get(0).unwrap().end()..]))
+    }
+}
@@ -0,0 +1,102 @@
+//! Reference kinds of the simple `<open><value><close>` shape, like `[vulnerability:123]` or
+//! `^alert#123`. The matcher in `GitlabLink::new` is generated from [`REF_KINDS`], so a new kind
+//! only needs a row in the table.
+
+/// What the URL of a reference kind is relative to.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Scope {
+    /// `<server>/<namespace>/<project>`
+    Project,
+    /// `<server>/groups/<namespace>`, the prefix of a cross-group reference is the group path
+    Group,
+}
+
+pub(crate) struct RefKind {
+    /// Name of the kind, unique in the table, used for the regex capture group
+    pub name: &'static str,
+    /// Literal text in front of the value
+    pub open: &'static str,
+    /// Literal text after the value
+    pub close: &'static str,
+    /// Pattern of the value
+    pub value: &'static str,
+    /// Path appended to the project or group URL, `{value}` is replaced by the encoded value
+    pub url: &'static str,
+    pub scope: Scope,
+}
+
+pub(crate) const REF_KINDS: &[RefKind] = &[
+    RefKind {
+        name: "vulnerability",
+        open: "[vulnerability:",
+        close: "]",
+        value: r"\d+",
+        url: "/-/security/vulnerabilities/{value}",
+        scope: Scope::Project,
+    },
+    RefKind {
+        name: "feature_flag",
+        open: "[feature_flag:",
+        close: "]",
+        value: r"\d+",
+        url: "/-/feature_flags/{value}/edit",
+        scope: Scope::Project,
+    },
+    RefKind {
+        name: "alert",
+        open: "^alert#",
+        close: "",
+        value: r"\d+\b",
+        url: "/-/alert_management/{value}/details",
+        scope: Scope::Project,
+    },
+    RefKind {
+        name: "incident",
+        open: "[incident:",
+        close: "]",
+        value: r"\d+",
+        url: "/-/issues/incident/{value}",
+        scope: Scope::Project,
+    },
+    RefKind {
+        name: "work_item",
+        open: "[work_item:",
+        close: "]",
+        value: r"\d+",
+        url: "/-/work_items/{value}",
+        scope: Scope::Project,
+    },
+    RefKind {
+        name: "contact",
+        open: "[contact:",
+        close: "]",
+        value: r"[^\]\s]+",
+        url: "/-/crm/contacts?search={value}",
+        scope: Scope::Group,
+    },
+    RefKind {
+        name: "iteration",
+        open: "*iteration:",
+        close: "",
+        value: r"\d+\b",
+        url: "/-/iterations/{value}",
+        scope: Scope::Group,
+    },
+];
+
+impl RefKind {
+    /// Name of the capture group holding the value.
+    pub fn group_name(&self) -> String {
+        format!("kind_{}", self.name)
+    }
+
+    /// Regex alternative matching a reference of this kind, without the project prefix.
+    pub fn pattern(&self) -> String {
+        format!("(?:{}(?P<{}>{}){})",
+            regex::escape(self.open),
+            self.group_name(),
+            self.value,
+            regex::escape(self.close),
+        )
+    }
+}
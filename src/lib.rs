@@ -1,14 +1,44 @@
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
-use mdbook::book::{Book, BookItem};
-use mdbook::errors::Result;
+use mdbook::book::{Book, BookItem, Chapter};
+use mdbook::errors::{Error, Result};
+use std::borrow::Cow;
+use std::ops::Range;
 use pulldown_cmark::{Event, Options, Parser, Tag};
-use regex::Regex;
+use regex::{Captures, Regex};
+
+use api::IssuableKind;
+use config::{Config, Container, Server, StateStyle};
+use directive::ChapterDirective;
+use kinds::{RefKind, Scope, REF_KINDS};
+
+mod api;
+mod cache;
+mod config;
+mod directive;
+mod kinds;
+mod remote;
 
 pub struct GitlabLink {
     re: Regex,
+    /// `<!-- gitlab-link:off -->` and `<!-- gitlab-link:on -->`, around text to leave alone
+    switch_re: Regex,
+    /// `<!-- gitlab-link: key=value ... -->` at the top of a chapter
+    directive_re: Regex,
+    /// Bare URLs in the text
+    url_re: Regex,
+    /// The path of a GitLab URL after the server, `group/project/-/issues/42`
+    url_path_re: Regex,
 }
 
-type Cfg<'a> = Option<&'a toml::map::Map<String, toml::Value>>;
+/// A single component of a namespace path, also the shape of a username. GitLab paths start
+/// with a letter, digit or underscore and never end with a period.
+const PATH_SEGMENT: &str = r"[a-zA-Z0-9_](?:[a-zA-Z0-9_\.-]*[a-zA-Z0-9_-])?";
+
+/// A comment on an issue or merge request, `#note_123` or ` (comment 123)`, right after its id.
+const NOTE_SUFFIX: &str = r"(?:\#note_\d+|\x20\(comment\x20\d+\))";
+
+/// A label name, optionally scoped like `priority::high`.
+const LABEL_NAME: &str = r"[a-zA-Z0-9_&?](?:[a-zA-Z0-9_\.&?-]*[a-zA-Z0-9_&-])?(?:::[a-zA-Z0-9_&?](?:[a-zA-Z0-9_\.&?-]*[a-zA-Z0-9_&-])?)*";
 
 enum RefType<'a> {
     Project(&'a str),
@@ -16,12 +46,88 @@ enum RefType<'a> {
         namespace: Option<&'a str>,
         project: Option<&'a str>,
         id: &'a str,
+        /// Id of a comment in the discussion
+        note: Option<&'a str>,
+        expand: Option<Expand>,
     },
     MergeRequest {
         namespace: Option<&'a str>,
         project: Option<&'a str>,
         id: &'a str,
+        /// Id of a comment in the discussion
+        note: Option<&'a str>,
+        expand: Option<Expand>,
+    },
+    User(&'a str),
+    Group(&'a str),
+    Milestone {
+        namespace: Option<&'a str>,
+        project: Option<&'a str>,
+        milestone: Milestone<'a>,
+    },
+    Commit {
+        namespace: Option<&'a str>,
+        project: Option<&'a str>,
+        sha: &'a str,
+    },
+    CommitRange {
+        namespace: Option<&'a str>,
+        project: Option<&'a str>,
+        from: &'a str,
+        to: &'a str,
+        /// `from..to` includes `from` itself, `from...to` does not
+        inclusive: bool,
+    },
+    Label {
+        namespace: Option<&'a str>,
+        project: Option<&'a str>,
+        /// Looked up for `~123`
+        name: Cow<'a, str>,
+    },
+    Snippet {
+        namespace: Option<&'a str>,
+        project: Option<&'a str>,
+        id: &'a str,
+    },
+    Epic {
+        /// The whole `namespace/project` prefix is the path of the group
+        namespace: Option<&'a str>,
+        project: Option<&'a str>,
+        id: &'a str,
     },
+    Extended {
+        namespace: Option<&'a str>,
+        project: Option<&'a str>,
+        kind: &'static RefKind,
+        value: &'a str,
+    },
+    WikiPage {
+        /// A single segment is a group, a deeper path is a project
+        path: Option<&'a str>,
+        page: &'a str,
+    },
+}
+
+/// The `+` and `+s` suffixes of issues and merge requests, shown with their title and details
+/// from the GitLab API.
+#[derive(Clone, Copy, PartialEq)]
+enum Expand {
+    Title,
+    Summary,
+}
+
+/// A reference that can't be linked, at a byte offset of the chapter text.
+#[derive(Debug)]
+struct Problem {
+    offset: usize,
+    message: String,
+}
+
+enum Milestone<'a> {
+    /// `%123`, the project-scoped milestone iid
+    Iid(&'a str),
+    /// `%v1.2` or `%"2026 Q3"`
+    Name(&'a str),
 }
 
 impl Default for GitlabLink {
@@ -32,161 +138,695 @@ impl Default for GitlabLink {
 
 impl GitlabLink {
     fn new() -> Self {
-        let re = Regex::new(r"(?x)
-            (?:                                     # issue or mr group
+        let re = Regex::new(&format!(r#"(?x)
+            (?:
+                @(?P<group>{seg}(?:/{seg})+)        # group, @group/subgroup
+            )
+            |
+            (?:
+                @(?P<user>{seg})                    # user, @username
+            )
+            |
+            (?:                                     # references within a project
                 (?:
-                    (?P<ns>
-                        (?:
-                            (?:[a-zA-Z0-9-_\.]+)
-                            (?:/(?P<subgroup>[a-zA-Z-_\.]+))?
-                        )
-                    /)?  # optional namespaces
-                    (?P<project>[a-zA-Z0-9-_\.]+)   # project
+                    (?:(?P<ns>{seg}(?:/{seg})*)/)?  # optional namespaces, any depth of subgroups
+                    (?P<project>{seg})              # project
                 )?                                  # optional namespace/project
                 (?:
-                    (?-x:#(?P<issue>\d+))           # issue id #42
+                    (?-x:#(?P<issue>\d+))\b         # issue id #42
+                    (?P<issue_note>{note})?         # optional comment, #42#note_123 or #42 (comment 123)
+                    (?P<issue_expand>\+(?:s\b|\B))?    # optional title, #42+, or title and summary, #42+s
                     |                               # or
-                    (?:!(?P<merge_request>\d+))     # merge request id !42
+                    (?:!(?P<merge_request>\d+))\b   # merge request id !42
+                    (?P<merge_request_note>{note})?
+                    (?P<merge_request_expand>\+(?:s\b|\B))?
+                    |
+                    (?:%(?P<milestone>{seg}))       # milestone iid or name, %42 or %v1.2
+                    |
+                    (?:%"(?P<milestone_quoted>[^"\n]+)")   # milestone name with spaces, %"2026 Q3"
+                    |
+                    (?:~(?P<label>{label}))         # label id or name, ~123, ~bug or ~priority::high
+                    |
+                    (?:~"(?P<label_quoted>[^"\n]+)")   # label name with spaces, ~"needs review"
+                    |
+                    (?:\$(?P<snippet>\d+))\b        # snippet id $42
+                    |
+                    (?:&(?P<epic>\d+))\b            # epic id &42, prefixed by a group rather than a project
+                    |
+                    {kinds}                         # the simple kinds in `REF_KINDS`, [vulnerability:42]
+                    |
+                    (?:@(?P<range_from>[0-9a-f]{{7,40}})(?P<range_dots>\.\.\.?)(?P<range_to>[0-9a-f]{{7,40}}))\b
+                                                    # commit range, project@9ba12248...b19a04f5
+                    |
+                    (?:@(?P<commit>[0-9a-f]{{7,40}}))\b    # commit, project@9ba12248
                 )
-            \b)
+            )
+            |
+            (?:
+                (?P<project_ref>{seg}(?:/{seg})+)>  # project ref, group/project>
+            )
+            |
+            (?:                                     # wiki page, [wiki_page:group/project:page]
+                \[wiki_page:(?:(?P<wiki_path>{seg}(?:/{seg})*):)?(?P<wiki_page>[^\]\n]+)\]
+            )
+            |
+            (?:                                     # commit range in the current project
+                (?P<bare_range_from>[0-9a-f]{{7,40}})(?P<bare_range_dots>\.\.\.?)(?P<bare_range_to>[0-9a-f]{{7,40}})\b
+            )
             |
             (?:
-                (?P<project_ref>[a-zA-Z0-9-_\.]+(/[a-zA-Z0-9-_\.]+)?/[a-zA-Z0-9-_\.]+)>   # project ref, group/project>
+                (?P<bare_commit>[0-9a-f]{{7,40}})\b # commit in the current project, 9ba12248
             )
-            ").unwrap();
+            "#,
+            seg = PATH_SEGMENT,
+            label = LABEL_NAME,
+            note = NOTE_SUFFIX,
+            kinds = REF_KINDS.iter().map(RefKind::pattern).collect::<Vec<_>>().join("\n|\n"),
+        )).unwrap();
+        let switch_re = Regex::new(r"<!--\s*gitlab-link:\s*(?P<state>on|off)\s*-->").unwrap();
+        let directive_re = Regex::new(r"^\s*<!--\s*gitlab-link:(?P<settings>.*?)-->[ \t]*(?:\r?\n)?").unwrap();
+        let url_re = Regex::new(r#"https?://[^\s<>()\[\]"'`]+"#).unwrap();
+        let url_path_re = Regex::new(&format!(r"(?x)
+            ^(?:
+                groups/(?P<group>{seg}(?:/{seg})*)/-/epics/(?P<epic>\d+)
+                |
+                (?P<path>{seg}(?:/{seg})+)/-/(?:
+                    issues/(?P<issue>\d+)(?:\#note_(?P<issue_note>\d+))?
+                    | merge_requests/(?P<merge_request>\d+)(?:\#note_(?P<merge_request_note>\d+))?
+                    | commit/(?P<commit>[0-9a-f]{{7,40}})
+                    | milestones/(?P<milestone>\d+)
+                    | snippets/(?P<snippet>\d+)
+                )
+            )\b", seg = PATH_SEGMENT)).unwrap();
         Self {
-            re
+            re,
+            switch_re,
+            directive_re,
+            url_re,
+            url_path_re,
+        }
+    }
+
+    /// Expands a prefix that is an alias from `[preprocessor.gitlab-link.aliases]`, and picks the
+    /// server the reference points to. Group references are routed by their own top-level
+    /// namespace, project references by the one of their namespace.
+    fn locate<'c>(&self, namespace: Option<&'c str>, project: Option<&'c str>, scope: Scope, cfg: &'c Config)
+        -> (&'c Server, Option<&'c str>, Option<&'c str>)
+    {
+        if let (None, Some(alias)) = (namespace, project.and_then(|p| cfg.aliases.get(p))) {
+            let server = alias.server.as_ref().map_or(&cfg.server, |name| &cfg.servers[name]);
+            let (namespace, project) = match alias.path.rsplit_once('/') {
+                Some((n, p)) => (Some(n), Some(p)),
+                None if alias.path.is_empty() => (None, None),
+                None => (None, Some(alias.path.as_str())),
+            };
+            return (server, namespace, project);
+        }
+
+        let top = match scope {
+            Scope::Project => namespace,
+            Scope::Group => namespace.or(project),
+        };
+        (top.map_or(&cfg.server, |path| cfg.server_for(path)), namespace, project)
+    }
+
+    /// The prefix as shown in the link text, aliases are kept unless `expand-aliases` is set.
+    fn display_prefix(&self, namespace: Option<&str>, project: Option<&str>, cfg: &Config) -> String {
+        let is_alias = namespace.is_none() && project.is_some_and(|p| cfg.aliases.contains_key(p));
+        if cfg.expand_aliases && is_alias {
+            let (server, namespace, project) = self.locate(namespace, project, Scope::Project, cfg);
+            return ref_prefix(
+                Some(namespace.unwrap_or(&server.namespace)),
+                Some(project.unwrap_or(&server.project)),
+            );
         }
+        ref_prefix(namespace, project)
     }
 
-    fn get_server_url<'a>(&self, cfg: Cfg<'a>) -> &'a str {
-        option_env!("CI_SERVER_URL").or_else(|| {
-            cfg.and_then(|m| {
-                m.get("gitlab-server-url").and_then(|s| s.as_str())
-            })
-        }).unwrap_or("")
+    /// Server and full path of the referenced project, falling back to the current namespace and
+    /// project.
+    fn project_path<'c>(&self, namespace: Option<&'c str>, project: Option<&'c str>, cfg: &'c Config)
+        -> (&'c Server, String)
+    {
+        let (server, namespace, project) = self.locate(namespace, project, Scope::Project, cfg);
+        (server, format!("{}/{}", namespace.unwrap_or(&server.namespace), project.unwrap_or(&server.project)))
     }
 
-    fn get_current_project<'a>(&self, cfg: Cfg<'a>) -> &'a str {
-        option_env!("CI_PROJECT_NAME").or_else(|| {
-            cfg.and_then(|m| {
-                m.get("gitlab-project-name").and_then(|s| s.as_str())
-            })
-        }).unwrap_or("")
+    /// URL of the referenced project, falling back to the current namespace and project.
+    fn project_url(&self, namespace: Option<&str>, project: Option<&str>, cfg: &Config) -> String {
+        let (server, path) = self.project_path(namespace, project, cfg);
+        format!("{}/{path}", server.url)
     }
 
-    fn get_current_namespace<'a>(&self, cfg: Cfg<'a>) -> &'a str {
-        option_env!("CI_PROJECT_NAMESPACE").or_else(|| {
-            cfg.and_then(|m| {
-                m.get("gitlab-project-namespace").and_then(|s| s.as_str())
-            })
-        }).unwrap_or("")
+    /// URL of a group given as a `namespace/project` prefix, falling back to the current namespace.
+    fn group_url(&self, namespace: Option<&str>, project: Option<&str>, cfg: &Config) -> String {
+        let (server, namespace, project) = self.locate(namespace, project, Scope::Group, cfg);
+        let path = match project {
+            Some(_) => ref_prefix(namespace, project),
+            None => server.namespace.clone(),
+        };
+        format!("{}/groups/{path}", server.url)
     }
 
-    fn resolve_ref<'a>(&self, ref_link: RefType<'a>, cfg: Cfg<'_>) -> String {
+    /// Links an issue or merge request, with its title and details for `+` and `+s`, and its state
+    /// as set by `state`, when the API has them.
+    #[allow(clippy::too_many_arguments)]
+    fn resolve_issuable(
+        &self,
+        kind: IssuableKind,
+        namespace: Option<&str>,
+        project: Option<&str>,
+        id: &str,
+        note: Option<&str>,
+        expand: Option<Expand>,
+        cfg: &Config,
+    ) -> String {
+        let sigil = match kind {
+            IssuableKind::Issue => '#',
+            IssuableKind::MergeRequest => '!',
+            IssuableKind::Milestone => '%',
+            IssuableKind::Label => '~',
+        };
+        let mut text = format!("{}{sigil}{id}{}",
+            self.display_prefix(namespace, project, cfg),
+            note.map(|n| format!(" (comment {n})")).unwrap_or_default(),
+        );
+
+        let wanted = expand.is_some() || cfg.state.is_some();
+        let info = cfg.api.as_ref().filter(|_| wanted).and_then(|api| {
+            let (server, path) = self.project_path(namespace, project, cfg);
+            api.issuable(server, &path, kind, id).flatten()
+        });
+        if let (Some(info), Some(expand)) = (&info, expand) {
+            let mut details = vec![text];
+            if expand == Expand::Summary {
+                details.extend(info.summary());
+            }
+            text = format!("{} ({})", escape_text(&info.title), escape_text(&details.join(" • ")));
+        }
+
+        let url = format!("{}/-/{}/{id}{}",
+            self.project_url(namespace, project, cfg),
+            kind.path(),
+            note.map(|n| format!("#note_{n}")).unwrap_or_default(),
+        );
+        // Anything else from the API stays out of the output, it would end up in a class name
+        let state = info.as_ref()
+            .map(|i| i.state.as_str())
+            .filter(|s| matches!(*s, "opened" | "closed" | "merged" | "locked"));
+        match cfg.state.zip(state) {
+            // Like GitLab, open ones go without a suffix
+            Some((StateStyle::Suffix, state @ ("closed" | "merged" | "locked"))) => {
+                format!("[{text} ({state})]({url})")
+            }
+            Some((StateStyle::Emoji, state)) => {
+                let emoji = match state {
+                    "opened" => " 🟢",
+                    "merged" => " 🟣",
+                    "closed" => " 🔴",
+                    _ => " 🔒",
+                };
+                format!("[{text}{emoji}]({url})")
+            }
+            Some((StateStyle::Class, state)) => {
+                format!("<a href=\"{url}\" class=\"gitlab-link-{state}\">{text}</a>")
+            }
+            _ => format!("[{text}]({url})"),
+        }
+    }
+
+    fn resolve_ref<'a>(&self, ref_link: RefType<'a>, cfg: &Config) -> String {
         match ref_link {
             RefType::Project(s) => {
-                format!("[{}>]({}/{})", s, self.get_server_url(cfg), s)
+                format!("[{}>]({}/{})", s, cfg.server_for(s).url, s)
+            }
+            RefType::Issue { namespace, project, id, note, expand } => {
+                self.resolve_issuable(IssuableKind::Issue, namespace, project, id, note, expand, cfg)
+            }
+            RefType::MergeRequest { namespace, project, id, note, expand } => {
+                self.resolve_issuable(IssuableKind::MergeRequest, namespace, project, id, note, expand, cfg)
+            }
+            RefType::Snippet { namespace, project, id } => {
+                format!("[{}${id}]({}/-/snippets/{id})",
+                    self.display_prefix(namespace, project, cfg),
+                    self.project_url(namespace, project, cfg),
+                )
+            }
+            RefType::Epic { namespace, project, id } => {
+                format!("[{}&{id}]({}/-/epics/{id})",
+                    self.display_prefix(namespace, project, cfg),
+                    self.group_url(namespace, project, cfg),
+                )
             }
-            RefType::Issue { namespace, project, id } => {
-                let issue = match (namespace, project) {
-                    (Some(n), Some(p)) => format!("{}/{}#{}", n, p, id),
-                    (None, Some(p)) => format!("{}#{}", p, id),
-                    (_, _) => format!("#{}", id)
+            RefType::Extended { namespace, project, kind, value } => {
+                let base = match kind.scope {
+                    Scope::Project => self.project_url(namespace, project, cfg),
+                    Scope::Group => self.group_url(namespace, project, cfg),
                 };
-
-                format!("[{}]({}/{}/{}/-/issues/{id})",
-                    issue,
-                    self.get_server_url(cfg),
-                    namespace.unwrap_or_else(|| self.get_current_namespace(cfg)),
-                    project.unwrap_or_else(|| self.get_current_project(cfg)),
+                // A literal `*` in the link text could pair up with another one as emphasis
+                format!("[{}{}{value}{}]({base}{})",
+                    self.display_prefix(namespace, project, cfg),
+                    kind.open.replace('*', "\\*"),
+                    kind.close,
+                    kind.url.replace("{value}", &url_encode(value)),
                 )
             }
-            RefType::MergeRequest { namespace, project, id } => {
-                let mr_name = match (namespace, project) {
-                    (Some(n), Some(p)) => format!("{n}/{p}!{id}"),
-                    (None, Some(p)) => format!("{p}!{id}"),
-                    _ => format!("!{id}"),
+            RefType::WikiPage { path, page } => {
+                let wiki = match path {
+                    None => self.project_url(None, None, cfg),
+                    Some(p) if p.contains('/') => format!("{}/{p}", cfg.server_for(p).url),
+                    Some(p) => format!("{}/groups/{p}", cfg.server_for(p).url),
                 };
-
-                format!("[{mr_name}]({}/{}/{}/-/merge_requests/{id})",
-                    self.get_server_url(cfg),
-                    namespace.unwrap_or_else(|| self.get_current_namespace(cfg)),
-                    project.unwrap_or_else(|| self.get_current_project(cfg)),
+                format!("[[wiki_page:{}{page}]]({wiki}/-/wikis/{})",
+                    path.map(|p| format!("{p}:")).unwrap_or_default(),
+                    wiki_slug(page),
+                )
+            }
+            RefType::User(username) => {
+                format!("[@{username}]({}/{username})", cfg.server.url)
+            }
+            RefType::Group(path) => {
+                format!("[@{path}]({}/{path})", cfg.server_for(path).url)
+            }
+            RefType::Commit { namespace, project, sha } => {
+                let at = if project.is_some() { "@" } else { "" };
+                format!("[{}{at}{}]({}/-/commit/{sha})",
+                    self.display_prefix(namespace, project, cfg),
+                    &sha[..sha.len().min(8)],
+                    self.project_url(namespace, project, cfg),
+                )
+            }
+            RefType::CommitRange { namespace, project, from, to, inclusive } => {
+                let at = if project.is_some() { "@" } else { "" };
+                let (dots, start) = if inclusive {
+                    ("..", format!("{from}%5E"))
+                } else {
+                    ("...", from.to_string())
+                };
+                format!("[{}{at}{}{dots}{}]({}/-/compare/{start}...{to})",
+                    self.display_prefix(namespace, project, cfg),
+                    &from[..from.len().min(8)],
+                    &to[..to.len().min(8)],
+                    self.project_url(namespace, project, cfg),
+                )
+            }
+            RefType::Milestone { namespace, project, milestone } => {
+                let prefix = self.display_prefix(namespace, project, cfg);
+                let project_url = self.project_url(namespace, project, cfg);
+                match milestone {
+                    Milestone::Iid(iid) => {
+                        format!("[{prefix}%{iid}]({project_url}/-/milestones/{iid})")
+                    }
+                    // Milestone pages are addressed by iid, a name can only be looked up
+                    Milestone::Name(name) => {
+                        format!("[{prefix}{}]({project_url}/-/milestones?search_title={}&state=all)",
+                            quote_name('%', name),
+                            url_encode(name),
+                        )
+                    }
+                }
+            }
+            RefType::Label { namespace, project, name } => {
+                format!("[{}{}]({}/-/issues?label_name%5B%5D={})",
+                    self.display_prefix(namespace, project, cfg),
+                    quote_name('~', &escape_text(&name)),
+                    self.project_url(namespace, project, cfg),
+                    url_encode(&name),
                 )
             }
         }
     }
 
-    fn replace(&self, content: &str, cfg: Cfg<'_>) -> String {
+    /// The setting a reference can't be linked without, for `strict`.
+    fn missing_setting(&self, ref_link: &RefType, cfg: &Config) -> Option<&'static str> {
+        let (namespace, project, scope) = match *ref_link {
+            RefType::Project(path) | RefType::Group(path) | RefType::WikiPage { path: Some(path), .. } => {
+                return cfg.server_for(path).url.is_empty().then_some("gitlab-server-url");
+            }
+            RefType::User(_) => return cfg.server.url.is_empty().then_some("gitlab-server-url"),
+            RefType::WikiPage { path: None, .. } => (None, None, Scope::Project),
+            RefType::Epic { namespace, project, .. } => (namespace, project, Scope::Group),
+            RefType::Extended { namespace, project, kind, .. } => (namespace, project, kind.scope),
+            RefType::Issue { namespace, project, .. }
+            | RefType::MergeRequest { namespace, project, .. }
+            | RefType::Milestone { namespace, project, .. }
+            | RefType::Commit { namespace, project, .. }
+            | RefType::CommitRange { namespace, project, .. }
+            | RefType::Label { namespace, project, .. }
+            | RefType::Snippet { namespace, project, .. } => (namespace, project, Scope::Project),
+        };
+        let (server, namespace, project) = self.locate(namespace, project, scope, cfg);
+        if server.url.is_empty() {
+            Some("gitlab-server-url")
+        } else if namespace.is_none() && server.namespace.is_empty() && (project.is_none() || scope == Scope::Project) {
+            Some("gitlab-project-namespace")
+        } else if project.is_none() && server.project.is_empty() && scope == Scope::Project {
+            Some("gitlab-project-name")
+        } else {
+            None
+        }
+    }
+
+    /// Asks GitLab whether an issue, merge request or milestone exists, for `check-existence`.
+    /// Only an answer that it doesn't is a problem, the build goes on when GitLab can't be asked.
+    fn check_existence(&self, ref_link: &RefType, cfg: &Config) -> Option<String> {
+        let api = cfg.api.as_ref()?;
+        let (namespace, project, kind, id) = match *ref_link {
+            RefType::Issue { namespace, project, id, .. } => (namespace, project, IssuableKind::Issue, id),
+            RefType::MergeRequest { namespace, project, id, .. } => (namespace, project, IssuableKind::MergeRequest, id),
+            RefType::Milestone { namespace, project, milestone: Milestone::Iid(iid) } => {
+                (namespace, project, IssuableKind::Milestone, iid)
+            }
+            _ => return None,
+        };
+        let (server, path) = self.project_path(namespace, project, cfg);
+        match api.issuable(server, &path, kind, id) {
+            Some(Some(_)) => None,
+            Some(None) => Some(format!("doesn't exist in {path}")),
+            None => {
+                log::warn!("can't check that {} {} exists in {}", kind.name(), id, path);
+                None
+            }
+        }
+    }
+
+    /// Turns a match of `re` in `text` into a reference, `None` when it turns out not to be one.
+    fn parse_ref<'t>(&self, caps: &Captures<'t>, text: &'t str, cfg: &Config) -> Option<RefType<'t>> {
+        let matched = caps.get(0).unwrap();
+        log::debug!("capture: ns: {:?}, project: {:?}, issue: {:?}, merge_request: {:?}\n{:?}",
+            caps.name("ns").map(|s| s.as_str()).unwrap_or(""),
+            caps.name("project").map(|s| s.as_str()).unwrap_or(""),
+            caps.name("issue").map(|s| s.as_str()).unwrap_or(""),
+            caps.name("merge_request").map(|s| s.as_str()).unwrap_or(""),
+            matched,
+        );
+
+        let namespace = caps.name("ns").map(|s| s.as_str());
+        let project = caps.name("project").map(|s| s.as_str());
+        // Milestones, labels, snippets and epics glued to a word, like `a~b`, are prose, and so is
+        // `50%off`, a number is no project
+        let standalone = is_ref_boundary(text, matched.start())
+            && !project.is_some_and(|p| p.bytes().all(|b| b.is_ascii_digit()));
+
+        let s = if let Some(m) = caps.name("project_ref") {
+            RefType::Project(m.as_str())
+        } else if let Some(page) = caps.name("wiki_page") {
+            RefType::WikiPage { path: caps.name("wiki_path").map(|m| m.as_str()), page: page.as_str() }
+        } else if let Some(id) = caps.name("issue") {
+            let note = caps.name("issue_note").map(|m| note_id(m.as_str()));
+            let expand = caps.name("issue_expand").map(|m| expand_from(m.as_str()));
+            RefType::Issue { namespace, project, id: id.as_str(), note, expand }
+        } else if let Some(id) = caps.name("merge_request") {
+            let note = caps.name("merge_request_note").map(|m| note_id(m.as_str()));
+            let expand = caps.name("merge_request_expand").map(|m| expand_from(m.as_str()));
+            RefType::MergeRequest { namespace, project, id: id.as_str(), note, expand }
+        } else if let Some(m) = caps.name("milestone").or_else(|| caps.name("milestone_quoted")) {
+            // `100%` or `%d` glued to a word is not a milestone, and neither is the `%20` escape of
+            // `hello%20world`
+            let name = m.as_str();
+            let is_escape = project.is_some()
+                && caps.name("milestone").is_some()
+                && name.len() >= 2
+                && name.bytes().take(2).all(|b| b.is_ascii_hexdigit())
+                && !name.bytes().all(|b| b.is_ascii_digit());
+            if !standalone || is_escape {
+                return None;
+            }
+            let milestone = if m.as_str().bytes().all(|b| b.is_ascii_digit()) {
+                Milestone::Iid(m.as_str())
+            } else {
+                Milestone::Name(m.as_str())
+            };
+            RefType::Milestone { namespace, project, milestone }
+        } else if let (Some(from), Some(to)) = (caps.name("range_from"), caps.name("range_to")) {
+            let min_length = cfg.commit_min_length;
+            if is_word_continuation(&text[matched.end()..])
+                || from.as_str().len() < min_length
+                || to.as_str().len() < min_length
+            {
+                return None;
+            }
+            RefType::CommitRange {
+                namespace,
+                project,
+                from: from.as_str(),
+                to: to.as_str(),
+                inclusive: &caps["range_dots"] == "..",
+            }
+        } else if let (Some(from), Some(to)) = (caps.name("bare_range_from"), caps.name("bare_range_to")) {
+            let min_length = cfg.commit_min_length;
+            if !is_ref_boundary(text, matched.start())
+                || is_word_continuation(&text[matched.end()..])
+                || !looks_like_sha(from.as_str(), min_length)
+                || !looks_like_sha(to.as_str(), min_length)
+            {
+                return None;
+            }
+            RefType::CommitRange {
+                namespace: None,
+                project: None,
+                from: from.as_str(),
+                to: to.as_str(),
+                inclusive: &caps["bare_range_dots"] == "..",
+            }
+        } else if let Some(sha) = caps.name("commit") {
+            // `@9ba12248` without a project is a user mention, handled above, and `dev@cafe1234.io`
+            // an e-mail address
+            if is_word_continuation(&text[matched.end()..]) || sha.as_str().len() < cfg.commit_min_length {
+                return None;
+            }
+            RefType::Commit { namespace, project, sha: sha.as_str() }
+        } else if let Some(sha) = caps.name("bare_commit") {
+            // The first group of a UUID, `550e8400-e29b-...`, is no commit either
+            if !is_ref_boundary(text, matched.start())
+                || is_word_continuation(&text[matched.end()..])
+                || !looks_like_sha(sha.as_str(), cfg.commit_min_length)
+            {
+                return None;
+            }
+            RefType::Commit { namespace: None, project: None, sha: sha.as_str() }
+        } else if let Some(name) = caps.name("label").or_else(|| caps.name("label_quoted")) {
+            // `~123` is a label by id, linked by the name the API has for it. Without one, or when
+            // there's no such label like in `~5 minutes`, it stays plain. A label named `123` is
+            // written `~"123"`.
+            let is_id = caps.name("label").is_some_and(|m| m.as_str().bytes().all(|b| b.is_ascii_digit()));
+            if !standalone {
+                return None;
+            }
+            let name = if is_id {
+                let (server, path) = self.project_path(namespace, project, cfg);
+                let label = cfg.api.as_ref()?.issuable(server, &path, IssuableKind::Label, name.as_str()).flatten()?;
+                Cow::Owned(label.title)
+            } else {
+                Cow::Borrowed(name.as_str())
+            };
+            RefType::Label { namespace, project, name }
+        } else if let Some(id) = caps.name("snippet") {
+            // `costs $5` is money, a bare `$5` is only a snippet with `bare-snippets`, and even then
+            // `$5.00` and `$5,000` are not
+            if !standalone
+                || (project.is_none() && !cfg.bare_snippets)
+                || is_decimal_continuation(&text[matched.end()..])
+            {
+                return None;
+            }
+            RefType::Snippet { namespace, project, id: id.as_str() }
+        } else if let Some(id) = caps.name("epic") {
+            if !standalone {
+                return None;
+            }
+            RefType::Epic { namespace, project, id: id.as_str() }
+        } else if let Some(path) = caps.name("group") {
+            if !is_ref_boundary(text, matched.start()) {
+                return None;
+            }
+            RefType::Group(path.as_str())
+        } else if let Some(username) = caps.name("user") {
+            // `ops@example.com` is an e-mail address, not a mention
+            if !is_ref_boundary(text, matched.start()) {
+                return None;
+            }
+            RefType::User(username.as_str())
+        } else if let Some((kind, value)) = REF_KINDS.iter().find_map(|kind| {
+            caps.name(&kind.group_name()).map(|value| (kind, value.as_str()))
+        }) {
+            RefType::Extended { namespace, project, kind, value }
+        } else {
+            return None;
+        };
+
+        Some(s)
+    }
+
+    /// Turns a URL on the server of the book into the reference GitLab would show for it, made
+    /// relative to the current project. Returns the length of the URL it stands for, which may
+    /// be followed by punctuation.
+    fn parse_url<'u>(&self, url: &'u str, cfg: &'u Config) -> Option<(RefType<'u>, usize)> {
+        if cfg.server.url.is_empty() {
+            return None;
+        }
+        let rest = url.strip_prefix(cfg.server.url.as_str())?.strip_prefix('/')?;
+        let caps = self.url_path_re.captures(rest)?;
+        let end = caps.get(0).unwrap().end();
+        // `.../-/issues/42/designs` or `.../-/issues/42?tab=x` is not the issue itself, only the
+        // punctuation of the sentence may follow
+        if !rest[end..].chars().all(|c| matches!(c, '.' | ',' | ';' | ':' | '!' | '?')) {
+            return None;
+        }
+
+        let s = if let (Some(group), Some(id)) = (caps.name("group"), caps.name("epic")) {
+            let (namespace, project) = match group.as_str() {
+                g if g == cfg.server.namespace => (None, None),
+                g => match g.rsplit_once('/') {
+                    Some((n, p)) => (Some(n), Some(p)),
+                    None => (None, Some(g)),
+                },
+            };
+            RefType::Epic { namespace, project, id: id.as_str() }
+        } else {
+            let (namespace, project) = caps.name("path")?.as_str().rsplit_once('/')?;
+            let (namespace, project) = if namespace != cfg.server.namespace {
+                (Some(namespace), Some(project))
+            } else if project != cfg.server.project {
+                (None, Some(project))
+            } else {
+                (None, None)
+            };
+            let caps_str = |name| caps.name(name).map(|m| m.as_str());
+            if let Some(id) = caps_str("issue") {
+                RefType::Issue { namespace, project, id, note: caps_str("issue_note"), expand: None }
+            } else if let Some(id) = caps_str("merge_request") {
+                RefType::MergeRequest { namespace, project, id, note: caps_str("merge_request_note"), expand: None }
+            } else if let Some(sha) = caps_str("commit") {
+                RefType::Commit { namespace, project, sha }
+            } else if let Some(iid) = caps_str("milestone") {
+                RefType::Milestone { namespace, project, milestone: Milestone::Iid(iid) }
+            } else if let Some(id) = caps_str("snippet") {
+                RefType::Snippet { namespace, project, id }
+            } else {
+                return None;
+            }
+        };
+
+        Some((s, url.len() - rest.len() + end))
+    }
+
+    /// Whether the text inside `tag` is left alone. References can't be linked inside code blocks,
+    /// links and images.
+    ///
+    /// Headings are opt-in with `link-headings`. mdbook strips tags before deriving the anchor of
+    /// a heading, so `## Fix crash (#431)` keeps its `fix-crash-431` anchor with the link in it.
+    /// mdbook also wraps the whole heading in a link to that anchor though, which the reference
+    /// then ends early: browsers close a link when another one starts.
+    fn is_skipped(&self, tag: &Tag, cfg: &Config) -> bool {
+        let container = match tag {
+            Tag::CodeBlock(_) | Tag::Link(_, _, _) | Tag::Image(_, _, _) => return true,
+            Tag::Heading(_, _, _) => return !cfg.link_headings,
+            Tag::BlockQuote => Container::BlockQuote,
+            Tag::List(_) => Container::List,
+            Tag::Table(_) => Container::Table,
+            Tag::FootnoteDefinition(_) => Container::FootnoteDefinition,
+            Tag::Emphasis => Container::Emphasis,
+            Tag::Strong => Container::Strong,
+            Tag::Strikethrough => Container::Strikethrough,
+            _ => return false,
+        };
+        cfg.skip.contains(&container)
+    }
+
+    /// Links the references in `content`. With `strict` or `check-existence`, the references that
+    /// can't be linked are returned instead.
+    fn replace(&self, content: &str, cfg: &Config) -> std::result::Result<String, Vec<Problem>> {
         let mut opts = Options::empty();
         opts.insert(Options::ENABLE_TABLES);
         opts.insert(Options::ENABLE_FOOTNOTES);
         opts.insert(Options::ENABLE_STRIKETHROUGH);
         opts.insert(Options::ENABLE_TASKLISTS);
 
-        let mut refs = vec![];
-        let mut in_skip = false;
+        let mut texts: Vec<Range<usize>> = vec![];
+        // Number of skipped elements the current event is nested in
+        let mut skip_depth = 0usize;
+        let mut switched_off = false;
+        let mut prev_is_text = false;
 
         let events = Parser::new_ext(content, opts);
         for (e, span) in events.into_offset_iter() {
-            match (in_skip, &e) {
-                (false,
-                    Event::Start(Tag::CodeBlock(_)) |
-                    Event::Start(Tag::Heading(_, _, _)) |
-                    Event::Start(Tag::Link(_, _, _)) |
-                    Event::Start(Tag::Image(_, _, _))
-                ) => {
-                    in_skip = true;
-                    continue;
+            let is_text = matches!(e, Event::Text(_));
+            match &e {
+                Event::Start(tag) if self.is_skipped(tag, cfg) => {
+                    skip_depth += 1;
                 }
 
-                (true,
-                    Event::End(Tag::CodeBlock(_)) |
-                    Event::End(Tag::Heading(_, _, _)) |
-                    Event::End(Tag::Link(_, _, _)) |
-                    Event::End(Tag::Image(_, _, _))
-                ) => {
-                    in_skip = false;
-                    continue;
+                Event::End(tag) if self.is_skipped(tag, cfg) => {
+                    skip_depth -= 1;
+                }
+
+                Event::Html(html) => {
+                    if let Some(caps) = self.switch_re.captures_iter(html).last() {
+                        switched_off = &caps["state"] == "off";
+                    }
                 }
 
-                (false, Event::Text(t)) => {
-                    for caps in self.re.captures_iter(t) {
-                        let matched = caps.get(0).unwrap();
-                        log::debug!("capture: ns: {:?}, project: {:?}, issue: {:?}, merge_request: {:?}\n{:?}",
-                            caps.name("ns").map(|s| s.as_str()).unwrap_or(""),
-                            caps.name("project").map(|s| s.as_str()).unwrap_or(""),
-                            caps.name("issue").map(|s| s.as_str()).unwrap_or(""),
-                            caps.name("merge_request").map(|s| s.as_str()).unwrap_or(""),
-                            matched,
-                        );
-
-                        let namespace = caps.name("ns").map(|s| s.as_str());
-                        let project = caps.name("project").map(|s| s.as_str());
-
-                        let s = if let Some(m) = caps.name("project_ref") {
-                            RefType::Project(m.as_str())
-                        } else if let Some(id) = caps.name("issue") {
-                            RefType::Issue { namespace, project, id: id.as_str() }
-                        } else if let Some(id) = caps.name("merge_request") {
-                            RefType::MergeRequest { namespace, project, id: id.as_str() }
-                        } else {
-                            continue;
-                        };
+                Event::Text(t) if skip_depth == 0 && !switched_off && **t == content[span.clone()] => {
+                    match texts.last_mut() {
+                        Some(last) if prev_is_text && last.end == span.start => last.end = span.end,
+                        _ => texts.push(span),
+                    }
+                }
 
-                        let link = self.resolve_ref(s, cfg);
+                _ => {}
+            }
+            prev_is_text = is_text;
+        }
+
+        let mut refs = vec![];
+        let mut problems = vec![];
+        for span in texts {
+            let t = &content[span.clone()];
 
-                        refs.push((link, (span.start + matched.start())..(span.start + matched.end())))
+            let urls: Vec<_> = self.url_re.find_iter(t).map(|m| m.range()).collect();
+            if cfg.shorten_urls {
+                for url in &urls {
+                    if let Some((s, len)) = self.parse_url(&t[url.clone()], cfg) {
+                        let link = self.resolve_ref(s, cfg);
+                        refs.push((link, (span.start + url.start)..(span.start + url.start + len)));
                     }
                 }
+            }
 
-                _ => {
+            for caps in self.re.captures_iter(t) {
+                let matched = caps.get(0).unwrap();
+                // `\#123` stays plain text, and renders without the backslash like on GitLab
+                if is_escaped(content, span.start + matched.start()) {
+                    continue;
+                }
+                // Nor is anything inside a URL, like the `#12` of `https://example.com/faq#12`
+                if urls.iter().any(|url| url.start < matched.end() && matched.start() < url.end) {
                     continue;
                 }
+                if let Some(s) = self.parse_ref(&caps, t, cfg) {
+                    let problem = match self.missing_setting(&s, cfg) {
+                        Some(key) => cfg.strict.then(|| format!("can't be linked without `{key}`")),
+                        None if cfg.check_existence => self.check_existence(&s, cfg),
+                        None => None,
+                    };
+                    if let Some(message) = problem {
+                        problems.push(Problem {
+                            offset: span.start + matched.start(),
+                            message: format!("`{}` {message}", matched.as_str()),
+                        });
+                    }
+                    let link = self.resolve_ref(s, cfg);
+                    refs.push((link, (span.start + matched.start())..(span.start + matched.end())))
+                }
             }
         }
 
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+
+        refs.sort_by_key(|(_, span)| span.start);
         let mut content = content.to_string();
         for (link, span) in refs.iter().rev() {
             let pre_content = &content[0..span.start];
@@ -194,7 +834,141 @@ impl GitlabLink {
             content = format!("{}{}{}", pre_content, link, post_content);
         }
 
-        content
+        Ok(content)
+    }
+
+    /// Links the references in a chapter, as set by its directive. Errors point at the chapter,
+    /// and the line and column of each reference that can't be linked.
+    fn replace_chapter(&self, chapter: &Chapter, cfg: &Config) -> Result<String> {
+        let path = chapter.source_path.as_deref()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|| chapter.name.clone());
+        let (directive, content) = ChapterDirective::parse(&self.directive_re, &chapter.content)
+            .map_err(|e| Error::msg(format!("{path}: {e}")))?;
+        if !directive.enabled {
+            return Ok(content.to_string());
+        }
+        let cfg = cfg.for_chapter(&directive).map_err(|e| Error::msg(format!("{path}: {e}")))?;
+
+        self.replace(content, &cfg).map_err(|problems| {
+            let start = chapter.content.len() - content.len();
+            let lines: Vec<_> = problems.iter()
+                .map(|p| {
+                    let (line, column) = line_column(&chapter.content, start + p.offset);
+                    format!("{path}:{line}:{column}: {}", p.message)
+                })
+                .collect();
+            Error::msg(lines.join("\n"))
+        })
+    }
+}
+
+/// The `namespace/project` part of a cross-project reference as it is displayed.
+fn ref_prefix(namespace: Option<&str>, project: Option<&str>) -> String {
+    match (namespace, project) {
+        (Some(n), Some(p)) => format!("{n}/{p}"),
+        (None, Some(p)) => p.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Displays a milestone or label name after its sigil, quoted when it has spaces.
+fn quote_name(sigil: char, name: &str) -> String {
+    if name.contains(char::is_whitespace) {
+        format!("{sigil}\"{name}\"")
+    } else {
+        format!("{sigil}{name}")
+    }
+}
+
+/// The URL path of a wiki page: GitLab puts dashes for spaces, and nested pages keep their
+/// slashes.
+fn wiki_slug(page: &str) -> String {
+    page.trim()
+        .split('/')
+        .map(|part| url_encode(&part.trim().replace(' ', "-")))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Percent-encodes everything but the unreserved characters of RFC 3986.
+pub(crate) fn url_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// The id in a `#note_123` or ` (comment 123)` suffix.
+fn note_id(suffix: &str) -> &str {
+    suffix.trim_matches(|c: char| !c.is_ascii_digit())
+}
+
+fn expand_from(suffix: &str) -> Expand {
+    match suffix {
+        "+s" => Expand::Summary,
+        _ => Expand::Title,
+    }
+}
+
+/// Escapes text from GitLab, like a title, so that it shows as is in the link text.
+fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '&' | '~' | '|') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Bare hex strings are only taken as commits when long enough and mixing letters and digits,
+/// so that numbers like `20261018` and words like `defaced` stay plain text.
+fn looks_like_sha(s: &str, min_length: usize) -> bool {
+    s.len() >= min_length
+        && s.bytes().any(|b| b.is_ascii_digit())
+        && s.bytes().any(|b| b.is_ascii_alphabetic())
+}
+
+/// Whether `rest`, the text right after a number, continues it with decimals or a thousands
+/// separator.
+fn is_decimal_continuation(rest: &str) -> bool {
+    let mut chars = rest.chars();
+    matches!(chars.next(), Some('.' | ','))
+        && chars.next().is_some_and(|c| c.is_ascii_digit())
+}
+
+/// The 1-based line and column of byte `offset` in `content`, the column counted in characters.
+fn line_column(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+/// Whether `rest`, the text right after a bare reference, glues it to more of a word, like the
+/// `-e29b` of a UUID or the `.txt` of a file name.
+fn is_word_continuation(rest: &str) -> bool {
+    let mut chars = rest.chars();
+    matches!(chars.next(), Some('-' | '/' | '.'))
+        && chars.next().is_some_and(char::is_alphanumeric)
+}
+
+/// Whether the character at `pos` is escaped by a backslash, which itself isn't escaped.
+fn is_escaped(content: &str, pos: usize) -> bool {
+    content[..pos].bytes().rev().take_while(|&b| b == b'\\').count() % 2 == 1
+}
+
+/// Whether a reference starting at `pos` in `text` stands on its own, i.e. it is not glued to
+/// a preceding word like the domain part of an e-mail address.
+fn is_ref_boundary(text: &str, pos: usize) -> bool {
+    match text[..pos].chars().next_back() {
+        Some(c) => !(c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | '/')),
+        None => true,
     }
 }
 
@@ -204,19 +978,380 @@ impl Preprocessor for GitlabLink {
     }
 
     fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
-        let cfg = ctx.config.get_preprocessor(self.name());
+        let cfg = Config::new(ctx.config.get_preprocessor(self.name()), &ctx.root)?;
 
+        // All chapters are done, so that the errors of the whole book are reported at once
+        let mut errors = vec![];
         book.for_each_mut(|item: &mut BookItem| {
-
             if let BookItem::Chapter(ref mut chapter) = *item {
-                chapter.content = self.replace(&chapter.content, cfg);
+                match self.replace_chapter(chapter, &cfg) {
+                    Ok(content) => chapter.content = content,
+                    Err(e) => errors.push(e.to_string()),
+                }
             }
         });
 
-        Ok(book)
+        if let Some(api) = &cfg.api {
+            api.save();
+        }
+
+        if errors.is_empty() {
+            Ok(book)
+        } else {
+            Err(Error::msg(errors.join("\n")))
+        }
     }
 
     fn supports_renderer(&self, renderer: &str) -> bool {
         renderer == "html"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::*;
+    use api::Client;
+    use cache::Cache;
+
+    const SERVER: &str = "https://gl.example.com";
+
+    /// A config for `team/proj` on `SERVER`, with `extra` lines of `book.toml`. The book config
+    /// wins so that the tests also pass in a pipeline.
+    fn try_config(extra: &str) -> Result<Config> {
+        let book = format!(r#"
+            gitlab-server-url = "{SERVER}"
+            gitlab-project-namespace = "team"
+            gitlab-project-name = "proj"
+            prefer-book-config = true
+            git-remote = false
+            api = false
+            {extra}
+        "#);
+        let table: toml::value::Table = toml::from_str(&book).unwrap();
+        Config::new(Some(&table), std::path::Path::new("."))
+    }
+
+    fn config(extra: &str) -> Config {
+        try_config(extra).unwrap()
+    }
+
+    fn replace(content: &str, extra: &str) -> String {
+        GitlabLink::new().replace(content, &config(extra)).unwrap()
+    }
+
+    #[test]
+    fn heading_with_link() {
+        let md = "## See [docs](x) for #12";
+        assert_eq!(replace(md, ""), md);
+        assert_eq!(
+            replace(md, "link-headings = true"),
+            format!("## See [docs](x) for [#12]({SERVER}/team/proj/-/issues/12)"),
+        );
+    }
+
+    #[test]
+    fn image_inside_link() {
+        let md = "[![img](i.png) #5](l) #6";
+        let expected = format!("[![img](i.png) #5](l) [#6]({SERVER}/team/proj/-/issues/6)");
+        assert_eq!(replace(md, ""), expected);
+        assert_eq!(replace(md, "link-headings = true"), expected);
+    }
+
+    #[test]
+    fn link_in_table_cell() {
+        let md = "| a | b |\n|---|---|\n| [x](y) #1 | #2 |";
+        let expected = format!(
+            "| a | b |\n|---|---|\n| [x](y) [#1]({SERVER}/team/proj/-/issues/1) | [#2]({SERVER}/team/proj/-/issues/2) |"
+        );
+        assert_eq!(replace(md, ""), expected);
+        assert_eq!(replace(md, "link-headings = true"), expected);
+    }
+
+    #[test]
+    fn glued_prefix() {
+        for md in ["50%off", "7~bug", "2$5", "12&3", "écafe%v1", "éapp~bug", "see hello%20world", "a%C3%A9"] {
+            assert_eq!(replace(md, ""), md);
+        }
+        assert_eq!(replace("app%20", ""), format!("[app%20]({SERVER}/team/app/-/milestones/20)"));
+        assert_eq!(
+            replace("see grp/app%v1 and app~bug", ""),
+            format!("see [grp/app%v1]({SERVER}/grp/app/-/milestones?search_title=v1&state=all) \
+                and [app~bug]({SERVER}/team/app/-/issues?label_name%5B%5D=bug)"),
+        );
+    }
+
+    #[test]
+    fn uuid_is_no_commit() {
+        for md in [
+            "550e8400-e29b-41d4-a716-446655440000",
+            "9ba12248/x",
+            "9ba12248.txt",
+            "9ba12248...b19a04f5-1",
+            "mail dev@cafe1234.io",
+            "app@9ba12248...b19a04f5.io",
+        ] {
+            assert_eq!(replace(md, ""), md);
+        }
+        for md in ["app@9ba12248", "app@9ba12248...b19a04f5"] {
+            assert_eq!(replace(md, "commit-min-length = 10"), md);
+        }
+        assert_eq!(
+            replace("app@9ba12248.", ""),
+            format!("[app@9ba12248]({SERVER}/team/app/-/commit/9ba12248)."),
+        );
+        assert_eq!(
+            replace("fixed in 9ba12248.", ""),
+            format!("fixed in [9ba12248]({SERVER}/team/proj/-/commit/9ba12248)."),
+        );
+    }
+
+    #[test]
+    fn numeric_label() {
+        for md in ["~123", "approx ~5 minutes", "grp/app~42"] {
+            assert_eq!(replace(md, ""), md);
+        }
+        assert_eq!(
+            replace(r#"~"123""#, ""),
+            format!(r#"[~123]({SERVER}/team/proj/-/issues?label_name%5B%5D=123)"#),
+        );
+    }
+
+    #[test]
+    fn label_by_id() {
+        let (url, requests) = mock_api(&[
+            ("/api/v4/projects/team%2Fproj/labels/123", r#"{"id": 123, "name": "needs review"}"#),
+            ("/api/v4/projects/grp%2Fapp/labels/7", r#"{"id": 7, "name": "a_b"}"#),
+        ]);
+        let cfg = api_config(&url, None);
+        assert_eq!(
+            GitlabLink::new().replace("~123, ~123, grp/app~7 and approx ~5 minutes", &cfg).unwrap(),
+            format!("[~\"needs review\"]({url}/team/proj/-/issues?label_name%5B%5D=needs%20review), \
+                [~\"needs review\"]({url}/team/proj/-/issues?label_name%5B%5D=needs%20review), \
+                [grp/app~a\\_b]({url}/grp/app/-/issues?label_name%5B%5D=a_b) and approx ~5 minutes"),
+        );
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn dollar_amounts() {
+        for md in ["costs $5 per month", "costs $5.", "$5.00", "$5,000", "$HOME"] {
+            assert_eq!(replace(md, ""), md);
+        }
+        for md in ["$5.00", "$5,000", "$HOME"] {
+            assert_eq!(replace(md, "bare-snippets = true"), md);
+        }
+        let snippet = format!("[$5]({SERVER}/team/proj/-/snippets/5)");
+        assert_eq!(replace("see $5.", "bare-snippets = true"), format!("see {snippet}."));
+        assert_eq!(replace("see $5", "bare-snippets = true"), format!("see {snippet}"));
+        assert_eq!(replace("see proj$5", ""), format!("see [proj$5]({SERVER}/team/proj/-/snippets/5)"));
+    }
+
+    #[test]
+    fn unknown_server_keys() {
+        let server = "[servers.public]\nurl = \"https://gitlab.com\"\nnamspaces = [\"gitlab-org\"]";
+        let e = try_config(server).err().unwrap();
+        assert!(e.to_string().contains("`servers.public.namspaces`"), "{e}");
+        assert!(try_config(&format!("deny-unknown-keys = false\n{server}")).is_ok());
+    }
+
+    /// The HTML renderer derives the anchor from the rendered heading, the link mustn't change it.
+    #[test]
+    fn linked_heading_anchor() {
+        let md = replace("## See docs for #12 here", "link-headings = true");
+        let html = mdbook::utils::render_markdown(&md, false);
+        let heading = html.trim_end().strip_prefix("<h2>").and_then(|h| h.strip_suffix("</h2>")).unwrap();
+        assert!(heading.contains(&format!("<a href=\"{SERVER}/team/proj/-/issues/12\">#12</a>")), "{heading}");
+        assert_eq!(
+            mdbook::utils::unique_id_from_content(heading, &mut Default::default()),
+            "see-docs-for-12-here",
+        );
+    }
+
+    /// Answers GitLab API requests on a local port with the `(path, body)` that matches, and 404
+    /// otherwise. Returns the server URL and the requests it got.
+    fn mock_api(responses: &'static [(&'static str, &'static str)]) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = requests.clone();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = String::new();
+                let mut reader = BufReader::new(&mut stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+                    request.push_str(&line);
+                    line.clear();
+                }
+                let path = request.split_whitespace().nth(1).unwrap_or_default().to_string();
+                received.lock().unwrap().push(request);
+                let response = match responses.iter().find(|(p, _)| *p == path) {
+                    Some((_, body)) => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len(),
+                    ),
+                    None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+                };
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        (url, requests)
+    }
+
+    /// A config for `team/proj` on `url`, with an API client that never touches the disk cache.
+    fn api_config(url: &str, token: Option<&str>) -> Config {
+        let mut cfg = config("");
+        cfg.server.url = url.to_string();
+        let cache = Cache::load(std::env::temp_dir().join("gitlab-link-test-unused.json"), Duration::from_secs(3600));
+        cfg.api = Some(Rc::new(Client::new(cache, token.map(String::from), false)));
+        cfg
+    }
+
+    #[test]
+    fn expand_with_api() {
+        let (url, requests) = mock_api(&[
+            ("/api/v4/projects/team%2Fproj/issues/42", r#"{
+                "title": "Crash on start",
+                "state": "opened",
+                "assignees": [{"name": "Alice"}, {"name": "Bob"}],
+                "milestone": {"title": "v1.2"},
+                "health_status": "on_track"
+            }"#),
+            ("/api/v4/projects/team%2Fproj/merge_requests/7", r#"{"title": "Fix [crash]", "state": "merged"}"#),
+        ]);
+        let cfg = api_config(&url, Some("sekret"));
+        let md = GitlabLink::new().replace("#42+, #42+s, !7+ and #9+.", &cfg).unwrap();
+        assert_eq!(md, format!(
+            "[Crash on start (#42)]({url}/team/proj/-/issues/42), \
+            [Crash on start (#42 • Alice, Bob • v1.2 • On track)]({url}/team/proj/-/issues/42), \
+            [Fix \\[crash\\] (!7)]({url}/team/proj/-/merge_requests/7) and \
+            [#9]({url}/team/proj/-/issues/9)."
+        ));
+
+        let requests = requests.lock().unwrap();
+        // `#42` is asked once for both references
+        assert_eq!(requests.len(), 3);
+        assert!(requests.iter().all(|r| r.to_lowercase().contains("private-token: sekret\r\n")), "{requests:?}");
+    }
+
+    #[test]
+    fn expand_without_token() {
+        let (url, requests) = mock_api(&[]);
+        let cfg = api_config(&url, None);
+        GitlabLink::new().replace("#42+", &cfg).unwrap();
+        assert!(!requests.lock().unwrap()[0].to_lowercase().contains("private-token"));
+    }
+
+    #[test]
+    fn expand_offline() {
+        // Nothing listens on the port once the listener is dropped
+        let url = format!("http://{}", TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap());
+        let cfg = api_config(&url, Some("sekret"));
+        assert_eq!(
+            GitlabLink::new().replace("#42+ and !7+s", &cfg).unwrap(),
+            format!("[#42]({url}/team/proj/-/issues/42) and [!7]({url}/team/proj/-/merge_requests/7)"),
+        );
+    }
+
+    #[test]
+    fn shorten_urls() {
+        let cases = [
+            ("team/proj/-/issues/42", "#42"),
+            ("team/other/-/merge_requests/3", "other!3"),
+            ("grp/sub/app/-/issues/1", "grp/sub/app#1"),
+            ("team/proj/-/issues/42#note_5", "#42 (comment 5)"),
+            ("team/proj/-/commit/9ba12248b19a04f5", "9ba12248"),
+        ];
+        for (path, text) in cases {
+            let md = format!("see {SERVER}/{path}, and");
+            assert_eq!(replace(&md, "shorten-urls = true"), format!("see [{text}]({SERVER}/{path}), and"));
+            assert_eq!(replace(&md, ""), md);
+        }
+        for md in [
+            format!("{SERVER}/team/proj/-/issues/42?tab=x"),
+            format!("{SERVER}/team/proj/-/issues/42#top"),
+            format!("{SERVER}/team/proj/-/issues/42/designs"),
+            "https://example.com/team/proj/-/issues/42".to_string(),
+        ] {
+            assert_eq!(replace(&md, "shorten-urls = true"), md);
+        }
+    }
+
+    #[test]
+    fn strict_error_position() {
+        let mut cfg = config("strict = true");
+        cfg.server.project = String::new();
+        let chapter = Chapter::new("c", "# Título\n\nÀ voir : #12 et !3\n".to_string(), "src/c.md", vec![]);
+        assert_eq!(
+            GitlabLink::new().replace_chapter(&chapter, &cfg).unwrap_err().to_string(),
+            "src/c.md:3:10: `#12` can't be linked without `gitlab-project-name`\n\
+            src/c.md:3:17: `!3` can't be linked without `gitlab-project-name`",
+        );
+        // The directive gives the project
+        let chapter = Chapter::new("c", "<!-- gitlab-link: project=grp/app -->\n#12".to_string(), "src/c.md", vec![]);
+        assert!(GitlabLink::new().replace_chapter(&chapter, &cfg).is_ok());
+    }
+
+    #[test]
+    fn check_existence() {
+        let (url, _) = mock_api(&[("/api/v4/projects/grp%2Fapp/issues/1", r#"{"title": "Exists"}"#)]);
+        let mut cfg = api_config(&url, None);
+        cfg.check_existence = true;
+        let content = "<!-- gitlab-link: project=grp/app -->\n# Título\n\nÀ voir : #1 et #404\n";
+        let chapter = Chapter::new("c", content.to_string(), "src/c.md", vec![]);
+        assert_eq!(
+            GitlabLink::new().replace_chapter(&chapter, &cfg).unwrap_err().to_string(),
+            "src/c.md:4:16: `#404` doesn't exist in grp/app",
+        );
+    }
+
+    #[test]
+    fn check_existence_offline() {
+        let url = format!("http://{}", TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap());
+        let mut cfg = api_config(&url, None);
+        cfg.check_existence = true;
+        let chapter = Chapter::new("c", "#1 and #404".to_string(), "src/c.md", vec![]);
+        assert_eq!(
+            GitlabLink::new().replace_chapter(&chapter, &cfg).unwrap(),
+            format!("[#1]({url}/team/proj/-/issues/1) and [#404]({url}/team/proj/-/issues/404)"),
+        );
+    }
+
+    #[test]
+    fn state() {
+        let (url, _) = mock_api(&[
+            ("/api/v4/projects/team%2Fproj/issues/1", r#"{"title": "a", "state": "opened"}"#),
+            ("/api/v4/projects/team%2Fproj/issues/2", r#"{"title": "b", "state": "closed"}"#),
+            ("/api/v4/projects/team%2Fproj/merge_requests/3", r#"{"title": "c", "state": "merged"}"#),
+            ("/api/v4/projects/team%2Fproj/issues/4", r#"{"title": "d", "state": "x\" onclick=\"y"}"#),
+        ]);
+        let md = "#1 #2 !3 #4 #5";
+        let issue = |id| format!("{url}/team/proj/-/issues/{id}");
+        let mr = format!("{url}/team/proj/-/merge_requests/3");
+        let with_style = |style: StateStyle| {
+            let mut cfg = api_config(&url, None);
+            cfg.state = Some(style);
+            GitlabLink::new().replace(md, &cfg).unwrap()
+        };
+
+        assert_eq!(with_style(StateStyle::Suffix), format!(
+            "[#1]({}) [#2 (closed)]({}) [!3 (merged)]({mr}) [#4]({}) [#5]({})",
+            issue(1), issue(2), issue(4), issue(5),
+        ));
+        assert_eq!(with_style(StateStyle::Emoji), format!(
+            "[#1 🟢]({}) [#2 🔴]({}) [!3 🟣]({mr}) [#4]({}) [#5]({})",
+            issue(1), issue(2), issue(4), issue(5),
+        ));
+        assert_eq!(with_style(StateStyle::Class), format!(
+            "<a href=\"{}\" class=\"gitlab-link-opened\">#1</a> <a href=\"{}\" class=\"gitlab-link-closed\">#2</a> \
+            <a href=\"{mr}\" class=\"gitlab-link-merged\">!3</a> [#4]({}) [#5]({})",
+            issue(1), issue(2), issue(4), issue(5),
+        ));
+    }
+}